@@ -4,21 +4,33 @@ use std::collections::{HashMap, HashSet, VecDeque};
 use adapter_protocol::*;
 use crate::prelude::*;
 use std::cell::RefCell;
+use std::io::{BufRead, Write};
 use crate::expressions::{self, FormatSpec, PreparedExpression};
 use serde::{Serialize, Deserialize};
 
+const PAGE_SIZE: Address = 0x1000;
+/* Widest scalar write we need to guard against straddling a page boundary */
+const MAX_ACCESS_WIDTH: Address = 8;
+
 /* Checkpoints are created before the actual memory write */
 #[derive(Clone)]
 pub struct Checkpoint {
+    /// Monotonically increasing id assigned by `Checkpoints::push_checkpoint`. Stable
+    /// across ring-buffer eviction, unlike a position in `checkpoints`.
+    pub id: u64,
     pub pc: Address,
     pub last_access: Option<Address>,
     pub frames: Vec<SBFrame>,
     pub registers: SBValueList,
+    /* Bytes of every page the about-to-happen write may touch, captured just before
+     * the single-step that performs it, so the write can be undone later. */
+    pub pre_write_bytes: Vec<(Address, Vec<u8>)>,
 }
 
 impl std::fmt::Debug for Checkpoint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Checkpoint")
+            .field("id", &self.id)
             .field("pc", &self.pc)
             .field("last_access", &self.last_access)
             .field("frames", &format!("\n{}", self.frames.iter()
@@ -26,20 +38,189 @@ impl std::fmt::Debug for Checkpoint {
                 .collect::<Vec<_>>()
                 .join("\n")))
             .field("registers", &"<SBValueList>")
+            .field("pre_write_bytes", &self.pre_write_bytes.iter()
+                .map(|(addr, bytes)| format!("{:#x}: {} bytes", addr, bytes.len()))
+                .collect::<Vec<_>>())
             .finish()
     }
 }
 
+/* Pages touched by a write that faults at `fault_address`. Usually just the one
+ * aligned page, but a write can straddle into the next page if it lands close
+ * enough to the boundary. */
+fn pages_touched(fault_address: Address) -> Vec<Address> {
+    let aligned = fault_address & !(PAGE_SIZE - 1);
+    let mut pages = vec![aligned];
+    if fault_address - aligned + MAX_ACCESS_WIDTH > PAGE_SIZE {
+        pages.push(aligned + PAGE_SIZE);
+    }
+    pages
+}
+
+/// How often a watched-page fault turns into a materialized `Checkpoint`.
+/// Faults that are skipped are still single-stepped over so the target keeps running;
+/// they just don't get recorded.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SamplingMode {
+    /// Record every watched write.
+    Always,
+    /// Record only every Nth watched write to a given page.
+    EveryN(u64),
+    /// Never record, just step over watched writes.
+    Off,
+}
+
+/// Disk-friendly stand-in for a stack frame: `SBFrame` is only meaningful against a
+/// live process, so all we keep is what a human wants to see in a write-timeline view.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoricalFrame {
+    pub function_name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl From<&SBFrame> for HistoricalFrame {
+    fn from(frame: &SBFrame) -> Self {
+        let line_entry = frame.line_entry();
+        HistoricalFrame {
+            function_name: frame.function_name().unwrap_or("<unknown>").to_string(),
+            file: line_entry.as_ref().map(|le| le.file_spec().path().to_string()),
+            line: line_entry.as_ref().map(|le| le.line()),
+        }
+    }
+}
+
+/// Disk-friendly, read-only stand-in for a `Checkpoint`. `SBFrame` and `SBValueList`
+/// can't be serialized and only mean anything against the live process they came from,
+/// so this flattens them into plain data for display/diffing after the fact.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoricalCheckpoint {
+    pub pc: Address,
+    pub last_access: Option<Address>,
+    pub registers: Vec<(String, String)>,
+    pub frames: Vec<HistoricalFrame>,
+}
+
+impl From<&Checkpoint> for HistoricalCheckpoint {
+    fn from(checkpoint: &Checkpoint) -> Self {
+        let mut registers = Vec::new();
+        for reg_set in checkpoint.registers.iter() {
+            for reg in reg_set.children() {
+                if let (Some(name), Some(value)) = (reg.name(), reg.value()) {
+                    registers.push((name.to_string(), value));
+                }
+            }
+        }
+
+        HistoricalCheckpoint {
+            pc: checkpoint.pc,
+            last_access: checkpoint.last_access,
+            registers,
+            frames: checkpoint.frames.iter().map(HistoricalFrame::from).collect(),
+        }
+    }
+}
+
+/// What the `GetCheckpoints` custom request reports for a single write: enough to
+/// render a "who wrote here and when" entry without shipping the whole frame/register
+/// state (that's what `save_checkpoints` is for) to the UI.
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckpointSummary {
+    /// `Checkpoint::id`, suitable for passing straight to `restore_checkpoint`. Unlike
+    /// a position in the checkpoint log, this stays valid even after the ring buffer
+    /// has evicted earlier entries -- it just won't resolve to anything anymore if the
+    /// checkpoint itself got evicted.
+    pub id: u64,
+    pub last_access: Option<Address>,
+    pub pc: Address,
+    pub function_name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl CheckpointSummary {
+    fn new(checkpoint: &Checkpoint) -> Self {
+        let top_frame = checkpoint.frames.first().map(HistoricalFrame::from);
+        CheckpointSummary {
+            id: checkpoint.id,
+            last_access: checkpoint.last_access,
+            pc: checkpoint.pc,
+            function_name: top_frame.as_ref()
+                .map(|frame| frame.function_name.clone())
+                .unwrap_or_else(|| "<unknown>".to_string()),
+            file: top_frame.as_ref().and_then(|frame| frame.file.clone()),
+            line: top_frame.as_ref().and_then(|frame| frame.line),
+        }
+    }
+}
+
+/// Arguments for the `GetCheckpoints` custom DAP request. With no filter, returns the
+/// whole write timeline; `address` narrows it to writes at that exact address, `page`
+/// to writes anywhere in that page.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GetCheckpointsArguments {
+    pub address: Option<Address>,
+    pub page: Option<Address>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GetCheckpointsResponseBody {
+    pub checkpoints: Vec<CheckpointSummary>,
+    pub watched_pages: Vec<Address>,
+}
+
+/// Controls how much of the write timeline `Checkpoints` actually keeps around.
+/// Set from the launch config's `checkpointPolicy` and can be changed at runtime via
+/// `DebugSession::set_checkpoint_policy` without needing to re-arm the `mprotect` traps.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CheckpointPolicy {
+    /// Ring buffer capacity: oldest checkpoints are evicted once this is exceeded.
+    pub max_checkpoints: usize,
+    pub sampling: SamplingMode,
+}
+
+impl Default for CheckpointPolicy {
+    fn default() -> Self {
+        CheckpointPolicy {
+            max_checkpoints: 1024,
+            sampling: SamplingMode::Always,
+        }
+    }
+}
+
+/// A watch armed via an expression (`myvec[3].field`) rather than a raw address, so
+/// checkpoint output can name the thing that was written to instead of just its address.
+pub(super) struct WatchedExpression {
+    pub expr: String,
+    pub pages: HashSet<Address>,
+}
+
 pub(super) struct Checkpoints {
     pub watch_pages: HashSet<Address>,
-    pub checkpoints: Vec<Checkpoint>,
+    pub watched_expressions: Vec<WatchedExpression>,
+    pub checkpoints: VecDeque<Checkpoint>,
+    pub policy: CheckpointPolicy,
+    /// Per-watched-page fault counter, used by `SamplingMode::EveryN`.
+    pub fault_counts: HashMap<Address, u64>,
+    /// Protection to give each watched page back when it is unwatched. `add_watch_page`
+    /// records read-write (0x3) for every page, since the page's real prior protection
+    /// can't be queried.
+    pub original_protection: HashMap<Address, u8>,
+    /// Next id to hand out in `push_checkpoint`. Keeps counting up across evictions, so
+    /// a `Checkpoint::id` never gets reused for a different write.
+    next_id: u64,
 }
 
 impl Checkpoints {
     pub(super) fn new() -> Self {
         Checkpoints {
             watch_pages: HashSet::new(),
-            checkpoints: Vec::new(),
+            watched_expressions: Vec::new(),
+            checkpoints: VecDeque::new(),
+            policy: CheckpointPolicy::default(),
+            fault_counts: HashMap::new(),
+            original_protection: HashMap::new(),
+            next_id: 0,
         }
     }
 
@@ -48,41 +229,127 @@ impl Checkpoints {
             checkpoint.last_access.map(|last_access| last_access == address).unwrap_or(false)
         })
     }
+
+    /// The expression (if any) whose watched pages cover `address`, for reporting
+    /// "write to `expr`" instead of a bare fault address.
+    pub(super) fn describe_watch(&self, address: Address) -> Option<&str> {
+        let aligned = address & !0xFFF;
+        self.watched_expressions.iter()
+            .find(|watch| watch.pages.contains(&aligned))
+            .map(|watch| watch.expr.as_str())
+    }
+
+    fn push_checkpoint(&mut self, mut checkpoint: Checkpoint) {
+        checkpoint.id = self.next_id;
+        self.next_id += 1;
+        self.checkpoints.push_back(checkpoint);
+        for _ in 0..excess_checkpoints(self.checkpoints.len(), self.policy.max_checkpoints) {
+            self.checkpoints.pop_front();
+        }
+    }
+}
+
+/// How many entries `push_checkpoint` needs to evict from the front of the ring buffer
+/// to bring it back down to `max` after a push took it to `len`.
+fn excess_checkpoints(len: usize, max: usize) -> usize {
+    len.saturating_sub(max)
+}
+
+/// Pure `SamplingMode` decision used by `DebugSession::should_create_checkpoint_event`:
+/// given the fault count a page had *before* this fault, decide whether to materialize
+/// a checkpoint for it and what the page's fault count should become afterwards.
+fn sampling_decision(mode: SamplingMode, count_before: u64) -> (bool, u64) {
+    match mode {
+        SamplingMode::Off => (false, count_before),
+        SamplingMode::Always => (true, count_before),
+        SamplingMode::EveryN(n) if n > 0 => {
+            let count = count_before + 1;
+            (count % n == 0, count)
+        }
+        SamplingMode::EveryN(_) => (false, count_before),
+    }
 }
 
 impl DebugSession {
 
     pub (super) fn handle_checkpoint_event(&mut self, stopped_thread: &SBThread) -> bool {
-        if !self.should_create_checkpoint_event(stopped_thread) {
-            // self.console_message("should_create_checkpoint_event false");
-            return false;
-        }
+        let fault_address = match self.watched_fault_address(stopped_thread) {
+            Some(addr) => addr,
+            None => return false,
+        };
 
-        self.new_checkpoint().is_ok()
+        if self.should_create_checkpoint_event(fault_address) {
+            self.new_checkpoint(fault_address).is_ok()
+        } else {
+            // Sampled out by the policy: still have to step over the write or we'll
+            // just fault on the same instruction forever.
+            self.step_over_watched_write(fault_address).is_ok()
+        }
     }
 
-    pub(super) fn should_create_checkpoint_event(&self, stopped_thread: &SBThread) -> bool {
+    /// Returns the fault address if `stopped_thread` stopped on a SIGSEGV inside a
+    /// watched page, `None` otherwise.
+    pub(super) fn watched_fault_address(&self, stopped_thread: &SBThread) -> Option<Address> {
         let thread = stopped_thread;
 
         if thread.stop_reason() != StopReason::Signal {
-            return false;
+            return None;
         }
 
         // Check if the signal is SIGSEGV
         let signal = thread.stop_reason_data_at_index(0);
         if signal != 11 { // SIGSEGV
-            return false;
+            return None;
         }
 
-        let fault_address = match thread.current_fault_addr() {
-            Some(addr) => addr,
-            None => return false,
-        };
+        let fault_address = thread.current_fault_addr()?;
         self.console_message(format!("checkpoint_event fault addr {:#x}", fault_address));
 
         // Check if the faulting address is in a watched page
         let aligned_addr = fault_address & !0xFFF;
-        self.checkpoints.borrow().watch_pages.contains(&aligned_addr)
+        if self.checkpoints.borrow().watch_pages.contains(&aligned_addr) {
+            Some(fault_address)
+        } else {
+            None
+        }
+    }
+
+    /// Decides whether the write that faulted at `fault_address` should be materialized
+    /// as a full `Checkpoint`, per the current `CheckpointPolicy::sampling` mode. Advances
+    /// the per-page fault counter used by `SamplingMode::EveryN` as a side effect.
+    pub(super) fn should_create_checkpoint_event(&self, fault_address: Address) -> bool {
+        let aligned_addr = fault_address & !0xFFF;
+        let mut checkpoints = self.checkpoints.borrow_mut();
+        let count_before = checkpoints.fault_counts.get(&aligned_addr).copied().unwrap_or(0);
+        let (create, count) = sampling_decision(checkpoints.policy.sampling, count_before);
+        checkpoints.fault_counts.insert(aligned_addr, count);
+        create
+    }
+
+    pub fn checkpoint_policy(&self) -> CheckpointPolicy {
+        self.checkpoints.borrow().policy
+    }
+
+    /// Change retention/sampling at runtime; takes effect on the next watched fault,
+    /// no need to re-arm the `mprotect` traps.
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy) {
+        self.checkpoints.borrow_mut().policy = policy;
+    }
+
+    /// Reads the optional `checkpointPolicy` field out of the launch configuration and
+    /// applies it via `set_checkpoint_policy`, so launch.json's setting takes effect
+    /// without needing a separate runtime call. Called from the launch request handler;
+    /// a missing field keeps `CheckpointPolicy::default()`, a malformed one just logs
+    /// and falls back to whatever policy was already in effect.
+    pub(super) fn apply_launch_checkpoint_policy(&mut self, launch_args: &serde_json::Value) {
+        let value = match launch_args.get("checkpointPolicy") {
+            Some(value) => value,
+            None => return,
+        };
+        match serde_json::from_value::<CheckpointPolicy>(value.clone()) {
+            Ok(policy) => self.set_checkpoint_policy(policy),
+            Err(e) => self.console_error(format!("Ignoring invalid checkpointPolicy in launch config: {}", e)),
+        }
     }
 
     pub fn mprotect_memory(&self, address: u64, protection: u8) -> Result<(), Error> {
@@ -101,11 +368,27 @@ impl DebugSession {
         Ok(())
     }
 
+    /// `mprotect_memory` over every page in `pages`, attempting all of them even if an
+    /// earlier one fails rather than stopping at the first failure, and returning only
+    /// the first error. Pages whose call failed keep their previous protection.
+    fn mprotect_pages(&self, pages: &[Address], protection: u8) -> Result<(), Error> {
+        pages.iter()
+            .map(|&page| self.mprotect_memory(page, protection))
+            .fold(Ok(()), |acc: Result<(), Error>, r| acc.and(r))
+    }
+
     pub(super) fn add_watch_page(&mut self, address: u64) {
         // Add the address to the watch list
         let mut checkpoints = self.checkpoints.borrow_mut();
         let aligned_addr = address & 0xFFFFFFFFFFF000; // Ignore top byte and page-align
         checkpoints.watch_pages.insert(aligned_addr);
+        // Remember what this page's protection was before we clamp it down to read-only,
+        // so unwatch_expression() can put it back afterwards. We have no way to query the
+        // inferior's actual current protection through this mprotect-expression shim, so
+        // assume the common case (read-write, no exec) rather than inventing something
+        // riskier; only record it the first time, so re-watching an already-watched page
+        // doesn't clobber the real original value with our read-only one.
+        checkpoints.original_protection.entry(aligned_addr).or_insert(0x3);
         if let Err(e) = self.mprotect_memory(aligned_addr, 0x1) {
             self.console_error(format!("Failed to mprotect memory: {}", e));
             return;
@@ -113,62 +396,452 @@ impl DebugSession {
         self.console_message(format!("Added watch on address 0x{:X}", address));
     }
 
-    pub(super) fn new_checkpoint(&mut self) -> Result<(), Error> {
+    /// Watch an lvalue expression (e.g. `myvec[3].field`) instead of a raw address:
+    /// evaluates `expr` in `frame`, then arms every page its storage spans. Re-arming
+    /// (e.g. because a stack variable moved between calls) re-evaluates `expr` and
+    /// recomputes which pages to watch.
+    pub(super) fn add_watch_expression(&mut self, expr: &str, frame: &SBFrame) {
+        let prepared = match expressions::prepare_with_format(expr, FormatSpec::Default) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                self.console_error(format!("Failed to parse expression '{}': {}", expr, e));
+                return;
+            }
+        };
+        let native_expr = match &prepared {
+            PreparedExpression::Native(s) | PreparedExpression::Simple(s) => s.as_str(),
+            PreparedExpression::Python(_) => {
+                self.console_error(format!("'{}' is a Python expression and can't be watched", expr));
+                return;
+            }
+        };
+
+        let value = frame.evaluate_expression(native_expr);
+        if !value.is_valid() {
+            self.console_error(format!("Failed to evaluate '{}': {:#?}", expr, value));
+            return;
+        }
+
+        let address = value.address().load_address(&self.target);
+        let size = value.byte_size().max(1);
+
+        // A struct/array can straddle a page boundary, so watch every page it spans.
+        let mut pages = HashSet::new();
+        let mut page = address & !0xFFF;
+        while page < address + size {
+            pages.insert(page);
+            page += 0x1000;
+        }
+
+        // The object may have moved since the last time this expression was armed
+        // (e.g. a stack variable after a new call frame) -- drop its old pages first.
+        self.unwatch_expression(expr);
+
+        for &page in &pages {
+            self.add_watch_page(page);
+        }
+
+        self.checkpoints.borrow_mut().watched_expressions.push(WatchedExpression {
+            expr: expr.to_string(),
+            pages,
+        });
+    }
+
+    /// Drop the watch for `expr` and release any pages it was the last expression using
+    /// -- so re-arming a moved stack variable doesn't leave the old address trapped
+    /// (and generating unattributed checkpoints) forever.
+    fn unwatch_expression(&mut self, expr: &str) {
+        let released_pages = {
+            let mut checkpoints = self.checkpoints.borrow_mut();
+            let mut released = HashSet::new();
+            checkpoints.watched_expressions.retain(|watch| {
+                if watch.expr == expr {
+                    released.extend(watch.pages.iter().copied());
+                    false
+                } else {
+                    true
+                }
+            });
+            // Some other expression may still be watching one of these pages.
+            for watch in &checkpoints.watched_expressions {
+                for page in &watch.pages {
+                    released.remove(page);
+                }
+            }
+            let mut released_with_protection = Vec::new();
+            for page in &released {
+                checkpoints.watch_pages.remove(page);
+                // Put back the protection recorded in `original_protection` when the page
+                // was first watched (read-write, as add_watch_page assumes) -- it is only
+                // populated while the page is actually watched, so remove it here too.
+                let protection = checkpoints.original_protection.remove(page).unwrap_or(0x3);
+                released_with_protection.push((*page, protection));
+            }
+            released_with_protection
+        };
+
+        for (page, protection) in released_pages {
+            if let Err(e) = self.mprotect_memory(page, protection) {
+                self.console_error(format!("Failed to unwatch page {:#x}: {}", page, e));
+            }
+        }
+    }
+
+    pub(super) fn new_checkpoint(&mut self, fault_address: Address) -> Result<(), Error> {
 
         let process = self.target.process();
         let thread = process.selected_thread();
         let frame = thread.frame_at_index(0);
-        let fault_address = thread.current_fault_addr().ok_or("Failed to get fault address")?;
-        let aligned_addr = fault_address & !0xFFF;
-        let signals = process.unix_signals();
+
+        // Snapshot every page the upcoming write may clobber so it can be undone later.
+        let pre_write_bytes = pages_touched(fault_address)
+            .into_iter()
+            .map(|page| {
+                let mut buf = vec![0u8; PAGE_SIZE as usize];
+                process.read_memory(page, &mut buf)
+                    .map_err(|e| Error::from(format!("Failed to snapshot page {:#x}: {:?}", page, e)))?;
+                Ok((page, buf))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
 
         let checkpoint = Checkpoint {
+            // Assigned for real by push_checkpoint() below.
+            id: 0,
             pc: frame.pc_address().load_address(&self.target),
             frames: thread.frames().collect(),
             registers: frame.registers(),
             last_access: Some(fault_address),
+            pre_write_bytes,
         };
 
-        self.checkpoints.borrow_mut().checkpoints.push(checkpoint);
+        self.checkpoints.borrow_mut().push_checkpoint(checkpoint);
+
+        self.step_over_fault(fault_address)
+    }
+
+    /// mprotect/step/resume dance shared by `new_checkpoint` and
+    /// `step_over_watched_write`: temporarily allow the write on every page it may touch
+    /// (a write can straddle a page boundary, same as `pages_touched` accounts for when
+    /// snapshotting), single-step over it, then re-arm the traps and resume. Always
+    /// restores the mprotect/SIGSEGV-suppression state before returning, even if the
+    /// step itself fails, so a single failed step can't leave a page unwatched or real
+    /// SIGSEGVs silently suppressed forever.
+    fn step_over_fault(&mut self, fault_address: Address) -> Result<(), Error> {
+        let process = self.target.process();
+        let thread = process.selected_thread();
+        let signals = process.unix_signals();
+        // pages_touched() is a worst-case guess based on MAX_ACCESS_WIDTH and can include
+        // a neighboring page that isn't actually being watched (e.g. a watched value
+        // sits near the end of its page but the store instruction spills past it). Only
+        // ever mprotect pages we actually trapped -- an unwatched neighbor must be left
+        // exactly as we found it.
+        let pages: Vec<Address> = {
+            let checkpoints = self.checkpoints.borrow();
+            pages_touched(fault_address).into_iter()
+                .filter(|page| checkpoints.watch_pages.contains(page))
+                .collect()
+        };
+
+        // Grant write access to every page this write may touch, attempting all of them
+        // even if an earlier one fails, so one failure doesn't leave some pages opened up
+        // and others still read-only right before we bail out below.
+        if let Err(e) = self.mprotect_pages(&pages, 0x3) {
+            // Best-effort: put back whatever we did manage to open up.
+            for &page in &pages {
+                let _ = self.mprotect_memory(page, 0x1);
+            }
+            return Err(e);
+        }
 
-        self.mprotect_memory(aligned_addr, 0x3)?;
         // Suppress SIGSEGV while stepping over
         signals.set_should_suppress(11, true);
 
         // Need the sync mode here because we want to step a single instruction without getting another
         // processs Stopped event (normally LLDB stops with StopReason::Trace)
         self.before_resume();
-        if let Err(e) = self.with_sync_mode(|| {
-            thread.step_instruction(true)
-        }) {
+        let step_result = self.with_sync_mode(|| thread.step_instruction(true));
+
+        // Re-arm every page we opened up above, even the ones after a failing one --
+        // don't stop partway through and leave some of them writable.
+        let reprotect_result = self.mprotect_pages(&pages, 0x1);
+        // Continue execution and reactivate SIGSEGV
+        signals.set_should_suppress(11, false);
+
+        if let Err(e) = step_result {
             self.console_error(format!("Failed to step instruction: {}", e));
+            return Err(e.into());
+        }
+        reprotect_result?;
+
+        if let Err(e) = process.resume() {
+            self.console_error(format!("Failed to continue execution: {}", e));
             return Err(e.into())
         }
+        Ok(())
+    }
 
-        self.mprotect_memory(aligned_addr, 0x1)?;
+    /// Same mprotect/step/resume dance as `new_checkpoint`, but without recording
+    /// anything: used when the sampling policy decides this particular watched
+    /// write shouldn't be materialized.
+    fn step_over_watched_write(&mut self, fault_address: Address) -> Result<(), Error> {
+        self.step_over_fault(fault_address)
+    }
 
+    /* Roll machine state back to the point captured by the checkpoint with the given
+     * id, i.e. just before that checkpoint's write happened, and drop every checkpoint
+     * recorded after it so the log stays a consistent linear history. */
+    pub fn restore_checkpoint(&mut self, id: u64) -> Result<(), Error> {
+        // Undo every write from the most recently recorded checkpoint back down to this
+        // one, in reverse chronological order -- not just this checkpoint's own write --
+        // so pages touched by later checkpoints (possibly different pages altogether)
+        // end up back where they were too.
+        let (checkpoint, to_undo, position) = {
+            let checkpoints = self.checkpoints.borrow();
+            let position = checkpoints.checkpoints.iter().position(|cp| cp.id == id)
+                .ok_or_else(|| Error::from(format!("No checkpoint with id {} (may have been evicted)", id)))?;
+            let checkpoint = checkpoints.checkpoints[position].clone();
+            let to_undo: Vec<Checkpoint> = checkpoints.checkpoints.iter().skip(position).cloned().collect();
+            (checkpoint, to_undo, position)
+        };
+
+        let process = self.target.process();
+        let thread = process.selected_thread();
+        let frame = thread.frame_at_index(0);
+        let signals = process.unix_signals();
+
+        // We may be restoring in the middle of the suppress-and-step dance in
+        // new_checkpoint()/step_over_fault(); whatever happens below, never leave
+        // SIGSEGV suppression asserted behind us -- not even on a partial failure.
+        let result = (|| -> Result<(), Error> {
+            for undo in to_undo.iter().rev() {
+                for (address, bytes) in &undo.pre_write_bytes {
+                    process.write_memory(*address, bytes)
+                        .map_err(|e| Error::from(format!("Failed to restore page {:#x}: {:?}", address, e)))?;
+                }
+            }
+
+            self.restore_registers(&frame, &checkpoint.registers)?;
+
+            if !frame.set_pc(checkpoint.pc) {
+                return Err(Error::from(format!("Failed to restore PC to {:#x}", checkpoint.pc)));
+            }
+            Ok(())
+        })();
 
-        // Continue execution and reactivate SIGSEGV
         signals.set_should_suppress(11, false);
+        result?;
 
-        if let Err(e) = process.resume() {
-            self.console_error(format!("Failed to continue execution: {}", e));
-            return Err(e.into())
+        self.checkpoints.borrow_mut().checkpoints.truncate(position + 1);
+
+        Ok(())
+    }
+
+    /* Rewind past the most recently recorded write, undoing it and forgetting it. */
+    pub fn reverse_continue(&mut self) -> Result<(), Error> {
+        let id = match self.checkpoints.borrow().checkpoints.back() {
+            Some(checkpoint) => checkpoint.id,
+            None => return Err(Error::from("No checkpoints to reverse to")),
+        };
+        self.restore_checkpoint(id)?;
+        // restore_checkpoint() only drops checkpoints recorded *after* `id`; since `id`
+        // was the last checkpoint before the restore, drop that final entry too.
+        self.checkpoints.borrow_mut().checkpoints.pop_back();
+        Ok(())
+    }
+
+    fn restore_registers(&self, frame: &SBFrame, saved: &SBValueList) -> Result<(), Error> {
+        let live_registers = frame.registers();
+        for reg_set in saved.iter() {
+            for reg in reg_set.children() {
+                let name = match reg.name() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let value = match reg.value() {
+                    Some(value) => value,
+                    None => continue,
+                };
+                if let Some(live_reg) = live_registers.find_value_by_name(name) {
+                    live_reg.set_value_from_cstring(&value)
+                        .map_err(|e| Error::from(format!("Failed to restore register {}: {:?}", name, e)))?;
+                }
+            }
         }
         Ok(())
     }
 
     pub(super) fn print_checkpoint_by_last_access(&mut self, address: Address) {
-        if let Some(cp) = self.checkpoints.borrow().find_checkpoint_by_last_access(address) {
-            self.console_message(format!("{:#?}", cp));
+        let cp = self.checkpoints.borrow().find_checkpoint_by_last_access(address).cloned();
+        if let Some(cp) = cp {
+            let checkpoints = self.checkpoints.borrow();
+            let description = cp.last_access
+                .and_then(|addr| checkpoints.describe_watch(addr))
+                .map(|expr| format!("write to `{}`\n", expr))
+                .unwrap_or_default();
+            drop(checkpoints);
+            self.console_message(format!("{}{:#?}", description, cp));
+        }
+    }
+
+    /// Dump the current write timeline as newline-delimited JSON so it can be inspected
+    /// or diffed after the session ends.
+    pub fn save_checkpoints(&self, path: &std::path::Path) -> Result<(), Error> {
+        let file = std::fs::File::create(path)
+            .map_err(|e| Error::from(format!("Failed to create {}: {}", path.display(), e)))?;
+        let mut writer = std::io::BufWriter::new(file);
+        for checkpoint in self.checkpoints.borrow().checkpoints.iter() {
+            let historical = HistoricalCheckpoint::from(checkpoint);
+            serde_json::to_writer(&mut writer, &historical)
+                .map_err(|e| Error::from(format!("Failed to serialize checkpoint: {}", e)))?;
+            writer.write_all(b"\n")
+                .map_err(|e| Error::from(format!("Failed to write {}: {}", path.display(), e)))?;
         }
+        Ok(())
     }
 
-    pub(super) fn get_checkpoints(&mut self) {
-        // let checkpoints = self.checkpoints.borrow().checkpoints.clone();
-        self.handle_python_message(serde_json::json!({
-            "type": "GetCheckpoints",
-            "checkpoints": "test",
-        }));
+    /// Reload a previously saved write timeline. The result is a read-only,
+    /// `HistoricalCheckpoint` view: it's for display only, and is not tied to (and
+    /// cannot be restored into) a live process.
+    pub fn load_checkpoints(path: &std::path::Path) -> Result<Vec<HistoricalCheckpoint>, Error> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| Error::from(format!("Failed to open {}: {}", path.display(), e)))?;
+        std::io::BufReader::new(file).lines()
+            .map(|line| {
+                let line = line.map_err(|e| Error::from(format!("Failed to read {}: {}", path.display(), e)))?;
+                serde_json::from_str(&line)
+                    .map_err(|e| Error::from(format!("Failed to parse checkpoint: {}", e)))
+            })
+            .collect()
+    }
+
+    /// Handles the `GetCheckpoints` custom DAP request: hands the front-end the write
+    /// timeline, optionally filtered down to a single address or page, so it can render
+    /// a "who wrote here and when" view.
+    pub(super) fn get_checkpoints(&mut self, args: GetCheckpointsArguments) -> Result<GetCheckpointsResponseBody, Error> {
+        let checkpoints = self.checkpoints.borrow();
+        let aligned_page = args.page.map(|page| page & !0xFFF);
+
+        let matching = checkpoints.checkpoints.iter()
+            .filter(|cp| {
+                let matches_address = args.address.map(|addr| cp.last_access == Some(addr)).unwrap_or(true);
+                let matches_page = aligned_page.map(|page| {
+                    cp.last_access.map(|addr| addr & !0xFFF == page).unwrap_or(false)
+                }).unwrap_or(true);
+                matches_address && matches_page
+            })
+            .map(CheckpointSummary::new)
+            .collect();
+
+        Ok(GetCheckpointsResponseBody {
+            checkpoints: matching,
+            watched_pages: checkpoints.watch_pages.iter().cloned().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_touched_single_page_for_writes_away_from_the_boundary() {
+        // A write well clear of the end of its page only touches that one page.
+        assert_eq!(pages_touched(0x1000), vec![0x1000]);
+        assert_eq!(pages_touched(0x1500), vec![0x1000]);
+    }
+
+    #[test]
+    fn pages_touched_straddles_into_the_next_page_near_the_boundary() {
+        // An 8-byte write starting 4 bytes before the end of the page spills into the
+        // next one.
+        assert_eq!(pages_touched(0x1ffc), vec![0x1000, 0x2000]);
+        // Starting right at the last byte of the page straddles too.
+        assert_eq!(pages_touched(0x1fff), vec![0x1000, 0x2000]);
+        // Far enough before the boundary that even an 8-byte write fits within the page.
+        assert_eq!(pages_touched(0x1ff7), vec![0x1000]);
+    }
+
+    #[test]
+    fn excess_checkpoints_only_reports_what_is_over_the_cap() {
+        assert_eq!(excess_checkpoints(3, 5), 0);
+        assert_eq!(excess_checkpoints(5, 5), 0);
+        assert_eq!(excess_checkpoints(7, 5), 2);
+    }
+
+    #[test]
+    fn sampling_decision_always_and_off() {
+        assert_eq!(sampling_decision(SamplingMode::Always, 41), (true, 41));
+        assert_eq!(sampling_decision(SamplingMode::Off, 41), (false, 41));
+    }
+
+    #[test]
+    fn sampling_decision_every_n_counts_per_page() {
+        // Every 3rd fault (counts 1, 2, 3, ...) should materialize a checkpoint.
+        let mode = SamplingMode::EveryN(3);
+        assert_eq!(sampling_decision(mode, 0), (false, 1));
+        assert_eq!(sampling_decision(mode, 1), (false, 2));
+        assert_eq!(sampling_decision(mode, 2), (true, 3));
+        assert_eq!(sampling_decision(mode, 3), (false, 4));
+    }
+
+    #[test]
+    fn sampling_decision_every_n_zero_never_fires() {
+        assert_eq!(sampling_decision(SamplingMode::EveryN(0), 0), (false, 0));
+        assert_eq!(sampling_decision(SamplingMode::EveryN(0), 100), (false, 100));
+    }
+
+    fn sample_historical_checkpoint() -> HistoricalCheckpoint {
+        HistoricalCheckpoint {
+            pc: 0x4000,
+            last_access: Some(0x5000),
+            registers: vec![("rax".to_string(), "0x1".to_string())],
+            frames: vec![HistoricalFrame {
+                function_name: "main".to_string(),
+                file: Some("main.cpp".to_string()),
+                line: Some(42),
+            }],
+        }
+    }
+
+    #[test]
+    fn historical_checkpoint_json_round_trips() {
+        let checkpoint = sample_historical_checkpoint();
+        let json = serde_json::to_string(&checkpoint).expect("serialize");
+        let restored: HistoricalCheckpoint = serde_json::from_str(&json).expect("deserialize");
+        assert_eq!(restored.pc, checkpoint.pc);
+        assert_eq!(restored.last_access, checkpoint.last_access);
+        assert_eq!(restored.registers, checkpoint.registers);
+        assert_eq!(restored.frames.len(), checkpoint.frames.len());
+        assert_eq!(restored.frames[0].function_name, checkpoint.frames[0].function_name);
+        assert_eq!(restored.frames[0].file, checkpoint.frames[0].file);
+        assert_eq!(restored.frames[0].line, checkpoint.frames[0].line);
+    }
+
+    #[test]
+    fn load_checkpoints_reads_back_an_ndjson_timeline() {
+        let a = sample_historical_checkpoint();
+        let mut b = sample_historical_checkpoint();
+        b.pc = 0x4010;
+        b.last_access = Some(0x5010);
+
+        let mut path = std::env::temp_dir();
+        path.push("codelldb-checkpoints-load-test.ndjson");
+        std::fs::write(
+            &path,
+            format!(
+                "{}\n{}\n",
+                serde_json::to_string(&a).unwrap(),
+                serde_json::to_string(&b).unwrap()
+            ),
+        )
+        .expect("write temp ndjson file");
+
+        let loaded = DebugSession::load_checkpoints(&path).expect("load_checkpoints");
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].pc, a.pc);
+        assert_eq!(loaded[1].pc, b.pc);
+        assert_eq!(loaded[1].last_access, b.last_access);
     }
 }